@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use chrono::Utc;
 #[allow(unused_imports)]
@@ -25,19 +25,237 @@ const SQL_CREATE_TABLES: &str = "CREATE TABLE blocks (
                                  'prev_block_hash' BINARY,
                                  'hash' BINARY,
                                  'pub_key' BINARY,
-                                 'signature' BINARY);
+                                 'signature' BINARY,
+                                 'accumulated_difficulty' BIGINT NOT NULL DEFAULT 0);
             CREATE INDEX block_index ON blocks (id);
-            CREATE TABLE transactions (id INTEGER PRIMARY KEY AUTOINCREMENT, identity BINARY, confirmation BINARY, method TEXT, data TEXT, pub_key BINARY);
-            CREATE INDEX ids ON transactions (identity);";
+            CREATE TABLE transactions (id INTEGER PRIMARY KEY AUTOINCREMENT, block_id BIGINT, identity BINARY, confirmation BINARY, method TEXT, data TEXT, pub_key BINARY);
+            CREATE INDEX ids ON transactions (identity);
+            CREATE TABLE branch_blocks (
+                                 'id' BIGINT NOT NULL,
+                                 'timestamp' BIGINT NOT NULL,
+                                 'version' INT,
+                                 'difficulty' INTEGER,
+                                 'random' INTEGER,
+                                 'nonce' INTEGER,
+                                 'transaction' TEXT,
+                                 'prev_block_hash' BINARY,
+                                 'hash' BINARY NOT NULL PRIMARY KEY,
+                                 'pub_key' BINARY,
+                                 'signature' BINARY,
+                                 'accumulated_difficulty' BIGINT NOT NULL DEFAULT 0);
+            CREATE INDEX branch_block_index ON branch_blocks (id);
+            CREATE TABLE deployments ('bit' INTEGER NOT NULL PRIMARY KEY, 'state' TEXT NOT NULL, 'locked_in_height' BIGINT, 'active_since_height' BIGINT);";
+const SQL_CREATE_DEPLOYMENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS deployments \
+    ('bit' INTEGER NOT NULL PRIMARY KEY, 'state' TEXT NOT NULL, 'locked_in_height' BIGINT, 'active_since_height' BIGINT);";
+const SQL_CREATE_BRANCH_BLOCKS_TABLE: &str = "CREATE TABLE IF NOT EXISTS branch_blocks (
+                                 'id' BIGINT NOT NULL,
+                                 'timestamp' BIGINT NOT NULL,
+                                 'version' INT,
+                                 'difficulty' INTEGER,
+                                 'random' INTEGER,
+                                 'nonce' INTEGER,
+                                 'transaction' TEXT,
+                                 'prev_block_hash' BINARY,
+                                 'hash' BINARY NOT NULL PRIMARY KEY,
+                                 'pub_key' BINARY,
+                                 'signature' BINARY,
+                                 'accumulated_difficulty' BIGINT NOT NULL DEFAULT 0);
+            CREATE INDEX IF NOT EXISTS branch_block_index ON branch_blocks (id);";
+const SQL_ALTER_BLOCKS_ADD_ACCUMULATED_DIFFICULTY: &str = "ALTER TABLE blocks ADD COLUMN 'accumulated_difficulty' BIGINT NOT NULL DEFAULT 0;";
+const SQL_ALTER_TRANSACTIONS_ADD_BLOCK_ID: &str = "ALTER TABLE transactions ADD COLUMN 'block_id' BIGINT;";
+const SQL_GET_DEPLOYMENT_STATE: &str = "SELECT state, locked_in_height, active_since_height FROM deployments WHERE bit=? LIMIT 1;";
+const SQL_SET_DEPLOYMENT_STATE: &str = "INSERT OR REPLACE INTO deployments (bit, state, locked_in_height, active_since_height) VALUES (?, ?, ?, ?);";
 const SQL_ADD_BLOCK: &str = "INSERT INTO blocks (id, timestamp, version, difficulty, random, nonce, 'transaction',\
-                          prev_block_hash, hash, pub_key, signature) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
+                          prev_block_hash, hash, pub_key, signature, accumulated_difficulty) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
+const SQL_DELETE_BLOCK_BY_ID: &str = "DELETE FROM blocks WHERE id=?;";
 const SQL_GET_LAST_BLOCK: &str = "SELECT * FROM blocks ORDER BY id DESC LIMIT 1;";
-const SQL_ADD_TRANSACTION: &str = "INSERT INTO transactions (identity, confirmation, method, data, pub_key) VALUES (?, ?, ?, ?, ?)";
+const SQL_ADD_TRANSACTION: &str = "INSERT INTO transactions (block_id, identity, confirmation, method, data, pub_key) VALUES (?, ?, ?, ?, ?, ?)";
+const SQL_DELETE_TRANSACTIONS_BY_BLOCK: &str = "DELETE FROM transactions WHERE block_id=?;";
 const SQL_GET_BLOCK_BY_ID: &str = "SELECT * FROM blocks WHERE id=? LIMIT 1;";
+const SQL_GET_BLOCK_BY_HASH: &str = "SELECT * FROM blocks WHERE hash=? LIMIT 1;";
 const SQL_GET_LAST_FULL_BLOCK: &str = "SELECT * FROM blocks WHERE `transaction`<>'' ORDER BY id DESC LIMIT 1;";
 const SQL_GET_PUBLIC_KEY_BY_ID: &str = "SELECT pub_key FROM transactions WHERE identity = ? ORDER BY id DESC LIMIT 1;";
 const SQL_GET_ID_BY_ID: &str = "SELECT identity FROM transactions WHERE identity = ? ORDER BY id DESC LIMIT 1;";
 const SQL_GET_TRANSACTION_BY_ID: &str = "SELECT * FROM transactions WHERE identity = ? ORDER BY id DESC LIMIT 1;";
+const SQL_GET_ACCUMULATED_DIFFICULTY: &str = "SELECT accumulated_difficulty FROM blocks WHERE id=? LIMIT 1;";
+const SQL_ADD_BRANCH_BLOCK: &str = "INSERT OR REPLACE INTO branch_blocks (id, timestamp, version, difficulty, random, nonce, 'transaction',\
+                          prev_block_hash, hash, pub_key, signature, accumulated_difficulty) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);";
+const SQL_GET_BRANCH_BLOCK_BY_HASH: &str = "SELECT * FROM branch_blocks WHERE hash=? LIMIT 1;";
+const SQL_GET_BRANCH_ACCUMULATED_DIFFICULTY: &str = "SELECT accumulated_difficulty FROM branch_blocks WHERE hash=? LIMIT 1;";
+const SQL_DELETE_BRANCH_BLOCK: &str = "DELETE FROM branch_blocks WHERE hash=?;";
+const SQL_PRUNE_BRANCH_BLOCKS: &str = "DELETE FROM branch_blocks WHERE id<=?;";
+/// How many blocks behind the canonical tip a branch can fall before it's pruned from
+/// `branch_blocks` as hopelessly lost; keeps a spam of weak forks from growing the DB forever
+const BRANCH_PRUNE_DEPTH: u64 = 50;
+/// Size of the in-memory block LRU cache. The original request asked for this to be
+/// configurable via `Settings`, which this still doesn't do — `Settings` isn't in this
+/// checkout, so there's nowhere to add a real `get_block_cache_capacity()` getter to back it.
+/// Follow-up, not done: add that getter to `Settings` and read it here once `settings.rs`
+/// lands, the same way `DEFAULT_BLOCK_CACHE_CAPACITY` would then become just the fallback.
+const DEFAULT_BLOCK_CACHE_CAPACITY: usize = 256;
+/// Number of preceding blocks used to compute the median-time-past (BIP113)
+const MEDIAN_TIME_SPAN: u64 = 11;
+
+/// Rolling window (in blocks) used to measure version-bit signaling for soft-fork deployments
+const DEPLOYMENT_WINDOW: u64 = 100;
+/// Percentage of a window that must signal readiness before a deployment locks in
+const DEPLOYMENT_THRESHOLD_PCT: u64 = 75;
+
+/// A BIP9-style soft-fork deployment signaled through a low bit of `Block.version`
+struct Deployment {
+    bit: u8,
+    name: &'static str,
+}
+
+/// Known version-bit deployments. Add an entry here to gate a new consensus rule behind
+/// network signaling instead of a hard flag day.
+const DEPLOYMENTS: &[Deployment] = &[
+    Deployment { bit: 0, name: "csv_relative_locktime" },
+];
+
+// Deployment bit 0 ("csv_relative_locktime" above) is reserved for a BIP68/112-style
+// relative timelock on `Transaction`, enforced by reading its `relative_lock` field. That
+// field doesn't exist on `Transaction` in this checkout, so enforcement can't land yet; the
+// bit is reserved and signaling/activation already works, ready for the enforcement commit
+// once `transaction.rs` grows the field.
+
+/// Where a deployment sits in the BIP9 state machine: DEFINED (not yet measuring) ->
+/// STARTED (counting signals in the rolling window) -> LOCKED_IN (threshold crossed, waiting
+/// out one more window) -> ACTIVE (consensus rule is in effect).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeploymentState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+}
+
+impl DeploymentState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeploymentState::Defined => "DEFINED",
+            DeploymentState::Started => "STARTED",
+            DeploymentState::LockedIn => "LOCKED_IN",
+            DeploymentState::Active => "ACTIVE",
+        }
+    }
+
+    fn from_str(s: &str) -> DeploymentState {
+        match s {
+            "STARTED" => DeploymentState::Started,
+            "LOCKED_IN" => DeploymentState::LockedIn,
+            "ACTIVE" => DeploymentState::Active,
+            _ => DeploymentState::Defined,
+        }
+    }
+}
+
+/// Persisted progress of a single deployment through the BIP9 state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DeploymentStatus {
+    state: DeploymentState,
+    locked_in_height: Option<u64>,
+    active_since_height: Option<u64>,
+}
+
+/// Pure BIP9 state transition: given a deployment's current status, the height the chain is
+/// now at, and how many of the last window's blocks signaled (`set` out of `total`), returns
+/// what the status should become. Separated from [`Chain::update_deployments`] so the state
+/// machine itself can be unit tested without a `Chain`/DB.
+fn next_deployment_status(status: DeploymentStatus, height: u64, set: u64, total: u64) -> DeploymentStatus {
+    let mut new_status = status;
+    match status.state {
+        DeploymentState::Defined => {
+            if height + 1 >= DEPLOYMENT_WINDOW {
+                new_status.state = DeploymentState::Started;
+            }
+        }
+        DeploymentState::Started => {
+            if total > 0 && set * 100 / total >= DEPLOYMENT_THRESHOLD_PCT {
+                new_status.state = DeploymentState::LockedIn;
+                new_status.locked_in_height = Some(height);
+            }
+        }
+        DeploymentState::LockedIn => {
+            if height >= status.locked_in_height.unwrap_or(height) + DEPLOYMENT_WINDOW {
+                new_status.state = DeploymentState::Active;
+                new_status.active_since_height = Some(height);
+            }
+        }
+        DeploymentState::Active => {}
+    }
+    new_status
+}
+
+/// Small fixed-capacity cache evicting the least recently used entry once full. Used for the
+/// in-memory block cache instead of pulling in an external LRU crate.
+struct BoundedCache<K: std::hash::Hash + Eq + Clone, V> {
+    capacity: usize,
+    order: VecDeque<K>,
+    entries: HashMap<K, V>,
+}
+
+impl<K: std::hash::Hash + Eq + Clone, V> BoundedCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        BoundedCache { capacity, order: VecDeque::new(), entries: HashMap::new() }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(position).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if self.entries.contains_key(key) {
+            self.touch(key);
+            self.entries.get(key)
+        } else {
+            None
+        }
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        if self.entries.contains_key(&key) {
+            self.touch(&key);
+        } else {
+            if self.entries.len() >= self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+            self.order.push_back(key.clone());
+        }
+        self.entries.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &K) {
+        if self.entries.remove(key).is_some() {
+            if let Some(position) = self.order.iter().position(|k| k == key) {
+                self.order.remove(position);
+            }
+        }
+    }
+}
+
+/// Retracted and enacted blocks produced by walking two tips back to their common ancestor,
+/// modeled on Parity's TreeRoute/ImportRoute used to switch between chain branches.
+pub struct ImportRoute {
+    /// The common ancestor both tips share; still canonical, never retracted.
+    pub ancestor: Block,
+    /// Canonical blocks that are no longer on the best chain, highest first.
+    pub retracted: Vec<Block>,
+    /// Branch blocks that become canonical, lowest (closest to the ancestor) first.
+    pub enacted: Vec<Block>,
+}
+
+/// Median of `values`, i.e. the upper of the two middle elements when the count is even.
+/// Sorts `values` in place.
+fn median(values: &mut Vec<i64>) -> i64 {
+    values.sort_unstable();
+    values[values.len() / 2]
+}
 
 pub struct Chain {
     origin: Bytes,
@@ -48,6 +266,14 @@ pub struct Chain {
     max_height: u64,
     db: Connection,
     zones: RefCell<HashSet<String>>,
+    /// LRU cache of recently seen blocks, keyed by index, to spare SQLite repeated lookups of
+    /// the same recent blocks during mining/validation of locker blocks
+    block_cache: RefCell<BoundedCache<u64, Block>>,
+    /// Secondary hash -> index map so a block can be found in the cache by hash as well
+    block_hash_index: RefCell<HashMap<Vec<u8>, u64>>,
+    /// In-memory cache of each deployment's BIP9 state, read through to the `deployments`
+    /// table so progress survives restarts
+    deployments: RefCell<HashMap<u8, DeploymentStatus>>,
 }
 
 impl Chain {
@@ -64,6 +290,9 @@ impl Chain {
             max_height: 0,
             db,
             zones: RefCell::new(HashSet::new()),
+            block_cache: RefCell::new(BoundedCache::new(DEFAULT_BLOCK_CACHE_CAPACITY)),
+            block_hash_index: RefCell::new(HashMap::new()),
+            deployments: RefCell::new(HashMap::new()),
         };
         chain.init_db();
         chain
@@ -105,6 +334,9 @@ impl Chain {
                 error!("Version downgrade {}->{} is not supported!", block.version, self.version);
                 panic!();
             }
+            // An older DB may predate the accumulated_difficulty/block_id columns and the
+            // branch_blocks/deployments tables; make sure all of them exist either way
+            self.ensure_schema_upgrades();
             // Cache some info
             self.last_block = Some(block.clone());
             if block.transaction.is_some() {
@@ -112,30 +344,48 @@ impl Chain {
             } else {
                 self.last_full_block = self.get_last_full_block();
             }
+            self.update_deployments(self.max_height);
         }
     }
 
     fn migrate_db(&mut self, from: u32, to: u32) {
         debug!("Migrating DB from {} to {}", from, to);
+        self.ensure_schema_upgrades();
+    }
+
+    /// Brings an older on-disk schema up to date: adds the `accumulated_difficulty` column to
+    /// `blocks`, the `block_id` column to `transactions`, and creates the `branch_blocks` and
+    /// `deployments` tables if they don't already exist. Safe to call on an already-upgraded
+    /// DB, since `ALTER TABLE ADD COLUMN` on an existing column just errors harmlessly here.
+    fn ensure_schema_upgrades(&mut self) {
+        let _ = self.db.execute(SQL_ALTER_BLOCKS_ADD_ACCUMULATED_DIFFICULTY);
+        let _ = self.db.execute(SQL_ALTER_TRANSACTIONS_ADD_BLOCK_ID);
+        self.db.execute(SQL_CREATE_BRANCH_BLOCKS_TABLE).expect("Error creating branch_blocks table");
+        self.db.execute(SQL_CREATE_DEPLOYMENTS_TABLE).expect("Error creating deployments table");
     }
 
     pub fn add_block(&mut self, block: Block) {
         info!("Adding block:\n{:?}", &block);
+        let accumulated_difficulty = self.accumulated_difficulty_after(block.index.checked_sub(1), block.difficulty);
         self.blocks.push(block.clone());
         self.last_block = Some(block.clone());
         if block.transaction.is_some() {
             self.last_full_block = Some(block.clone());
         }
+        let index = block.index;
         let transaction = block.transaction.clone();
-        if self.add_block_to_table(block).is_ok() {
+        self.cache_block(&block);
+        if self.add_block_to_table(block, accumulated_difficulty).is_ok() {
             if let Some(transaction) = transaction {
-                self.add_transaction_to_table(&transaction).expect("Error adding transaction");
+                self.add_transaction_to_table(index, &transaction).expect("Error adding transaction");
             }
         }
+        self.update_deployments(index);
+        self.prune_stale_branch_blocks(index);
     }
 
     /// Adds block to blocks table
-    fn add_block_to_table(&mut self, block: Block) -> sqlite::Result<State> {
+    fn add_block_to_table(&mut self, block: Block, accumulated_difficulty: u64) -> sqlite::Result<State> {
         let mut statement = self.db.prepare(SQL_ADD_BLOCK)?;
         statement.bind(1, block.index as i64)?;
         statement.bind(2, block.timestamp as i64)?;
@@ -153,21 +403,285 @@ impl Chain {
         statement.bind(9, block.hash.as_slice())?;
         statement.bind(10, block.pub_key.as_slice())?;
         statement.bind(11, block.signature.as_slice())?;
+        statement.bind(12, accumulated_difficulty as i64)?;
         statement.next()
     }
 
+    /// Removes a block and its transaction from the canonical tables, used when retracting
+    /// blocks during a reorganization
+    fn delete_block_from_table(&mut self, block: &Block) -> sqlite::Result<State> {
+        let mut del_tx = self.db.prepare(SQL_DELETE_TRANSACTIONS_BY_BLOCK)?;
+        del_tx.bind(1, block.index as i64)?;
+        del_tx.next()?;
+        let mut del_block = self.db.prepare(SQL_DELETE_BLOCK_BY_ID)?;
+        del_block.bind(1, block.index as i64)?;
+        del_block.next()
+    }
+
     /// Adds transaction to transactions table
-    fn add_transaction_to_table(&mut self, t: &Transaction) -> sqlite::Result<State> {
+    fn add_transaction_to_table(&mut self, block_id: u64, t: &Transaction) -> sqlite::Result<State> {
         let mut statement = self.db.prepare(SQL_ADD_TRANSACTION)?;
-        statement.bind(1, t.identity.as_slice())?;
-        statement.bind(2, t.confirmation.as_slice())?;
-        statement.bind(3, t.method.as_ref() as &str)?;
-        statement.bind(4, t.data.as_ref() as &str)?;
-        statement.bind(5, t.pub_key.as_slice())?;
+        statement.bind(1, block_id as i64)?;
+        statement.bind(2, t.identity.as_slice())?;
+        statement.bind(3, t.confirmation.as_slice())?;
+        statement.bind(4, t.method.as_ref() as &str)?;
+        statement.bind(5, t.data.as_ref() as &str)?;
+        statement.bind(6, t.pub_key.as_slice())?;
+        statement.next()
+    }
+
+    /// Work a block of this difficulty contributes towards its branch's accumulated difficulty
+    fn block_work(difficulty: u32) -> u64 {
+        1u64 << difficulty.min(63)
+    }
+
+    /// Accumulated difficulty stored for a canonical block, if any
+    fn get_accumulated_difficulty(&self, index: u64) -> Option<u64> {
+        let mut statement = self.db.prepare(SQL_GET_ACCUMULATED_DIFFICULTY).ok()?;
+        statement.bind(1, index as i64).ok()?;
+        if statement.next().ok()? == State::Row {
+            return statement.read::<i64>(0).ok().map(|d| d as u64);
+        }
+        None
+    }
+
+    /// Accumulated difficulty of the current canonical tip
+    pub fn accumulated_difficulty(&self) -> u64 {
+        self.get_accumulated_difficulty(self.height()).unwrap_or(0)
+    }
+
+    fn accumulated_difficulty_after(&self, parent_index: Option<u64>, difficulty: u32) -> u64 {
+        let parent_work = match parent_index {
+            Some(index) => self.get_accumulated_difficulty(index).unwrap_or(0),
+            None => 0,
+        };
+        parent_work + Self::block_work(difficulty)
+    }
+
+    /// Accumulated difficulty of a non-canonical branch block, following its stored value
+    fn get_branch_accumulated_difficulty(&self, hash: &Bytes) -> Option<u64> {
+        let mut statement = self.db.prepare(SQL_GET_BRANCH_ACCUMULATED_DIFFICULTY).ok()?;
+        statement.bind(1, hash.as_slice()).ok()?;
+        if statement.next().ok()? == State::Row {
+            return statement.read::<i64>(0).ok().map(|d| d as u64);
+        }
+        None
+    }
+
+    /// Loads a non-canonical block previously stashed by [`Chain::handle_fork_block`]
+    fn get_branch_block_by_hash(&self, hash: &Bytes) -> Option<Block> {
+        let mut statement = self.db.prepare(SQL_GET_BRANCH_BLOCK_BY_HASH).ok()?;
+        statement.bind(1, hash.as_slice()).ok()?;
+        if statement.next().ok()? == State::Row {
+            return Self::get_block_from_statement(&mut statement);
+        }
+        None
+    }
+
+    /// Stashes a block that forked off the canonical chain into the `branch_blocks` side table,
+    /// tracking its accumulated difficulty so competing branches can be compared by weight
+    fn store_branch_block(&mut self, block: &Block) -> sqlite::Result<State> {
+        let is_canonical_parent = self.get_block(block.index.saturating_sub(1))
+            .map(|parent| parent.hash == block.prev_block_hash)
+            .unwrap_or(false);
+        let parent_work = if is_canonical_parent {
+            self.get_accumulated_difficulty(block.index.saturating_sub(1)).unwrap_or(0)
+        } else {
+            self.get_branch_accumulated_difficulty(&block.prev_block_hash).unwrap_or(0)
+        };
+        let accumulated_difficulty = parent_work + Self::block_work(block.difficulty);
+        let mut statement = self.db.prepare(SQL_ADD_BRANCH_BLOCK)?;
+        statement.bind(1, block.index as i64)?;
+        statement.bind(2, block.timestamp as i64)?;
+        statement.bind(3, block.version as i64)?;
+        statement.bind(4, block.difficulty as i64)?;
+        statement.bind(5, block.random as i64)?;
+        statement.bind(6, block.nonce as i64)?;
+        match &block.transaction {
+            None => { statement.bind(7, "")?; }
+            Some(transaction) => { statement.bind(7, transaction.to_string().as_str())?; }
+        }
+        statement.bind(8, block.prev_block_hash.as_slice())?;
+        statement.bind(9, block.hash.as_slice())?;
+        statement.bind(10, block.pub_key.as_slice())?;
+        statement.bind(11, block.signature.as_slice())?;
+        statement.bind(12, accumulated_difficulty as i64)?;
         statement.next()
     }
 
+    /// Removes a block from the `branch_blocks` side table, called once it has been enacted
+    /// onto the canonical chain or once it falls behind and can be pruned
+    fn delete_branch_block(&mut self, block: &Block) -> sqlite::Result<State> {
+        let mut statement = self.db.prepare(SQL_DELETE_BRANCH_BLOCK)?;
+        statement.bind(1, block.hash.as_slice())?;
+        statement.next()
+    }
+
+    /// Drops branch blocks that have fallen [`BRANCH_PRUNE_DEPTH`] or more blocks behind the
+    /// canonical tip: they can no longer plausibly out-weigh the canonical chain, so there's no
+    /// reason to keep persisting them
+    fn prune_stale_branch_blocks(&mut self, canonical_height: u64) {
+        let cutoff = canonical_height.saturating_sub(BRANCH_PRUNE_DEPTH);
+        if cutoff == 0 {
+            return;
+        }
+        if let Ok(mut statement) = self.db.prepare(SQL_PRUNE_BRANCH_BLOCKS) {
+            if statement.bind(1, cutoff as i64).is_ok() {
+                let _ = statement.next();
+            }
+        }
+    }
+
+    /// Walks the forked block's branch back to the point where it reconnects with our
+    /// canonical chain, and the canonical chain back to that same ancestor, producing the
+    /// retracted/enacted lists needed to reorganize onto the heavier branch
+    fn build_import_route(&self, tip: &Block) -> Option<ImportRoute> {
+        Self::compute_import_route(
+            tip.clone(),
+            self.height(),
+            |index| self.get_block(index),
+            |hash| self.get_branch_block_by_hash(hash),
+        )
+    }
+
+    /// Pure route-building logic behind [`Chain::build_import_route`], taking its canonical
+    /// and branch block lookups as closures so it can be exercised without a live `Chain`/DB.
+    fn compute_import_route(
+        tip: Block,
+        canonical_height: u64,
+        get_canonical: impl Fn(u64) -> Option<Block>,
+        get_branch: impl Fn(&Bytes) -> Option<Block>,
+    ) -> Option<ImportRoute> {
+        let mut enacted = vec![tip.clone()];
+        let mut cursor = tip;
+        loop {
+            let parent_index = cursor.index.checked_sub(1)?;
+            if let Some(canonical_parent) = get_canonical(parent_index) {
+                if canonical_parent.hash == cursor.prev_block_hash {
+                    let mut retracted = Vec::new();
+                    let mut index = canonical_height;
+                    while index > canonical_parent.index {
+                        if let Some(block) = get_canonical(index) {
+                            retracted.push(block);
+                        }
+                        index -= 1;
+                    }
+                    enacted.reverse();
+                    return Some(ImportRoute { ancestor: canonical_parent, retracted, enacted });
+                }
+            }
+            let parent = get_branch(&cursor.prev_block_hash)?;
+            if parent.index != parent_index {
+                warn!("Branch block {} claims prev_block_hash of index {} but points to block {}, aborting reorg", cursor.index, parent_index, parent.index);
+                return None;
+            }
+            enacted.push(parent.clone());
+            cursor = parent;
+        }
+    }
+
+    /// Rewinds the retracted blocks and replays the enacted ones inside a single DB
+    /// transaction, so a failed reorg leaves the database untouched. Each enacted block is
+    /// revalidated against its real predecessor in the new branch (tracked locally as
+    /// `parent`, starting at the common ancestor) rather than against `self.last_block`,
+    /// which still points at the old, already-retracted tip until the whole route commits.
+    /// For the same reason, the block cache/hash-index and `self.last_block`/`self.blocks`
+    /// are only mutated after a successful COMMIT, so a rolled-back reorg can't leave the
+    /// in-memory state diverged from the (reverted) database.
+    fn apply_import_route(&mut self, route: ImportRoute) -> bool {
+        if route.enacted.is_empty() {
+            return false;
+        }
+        info!("Reorganizing chain: retracting {} block(s), enacting {} block(s)", route.retracted.len(), route.enacted.len());
+        if self.db.execute("BEGIN;").is_err() {
+            error!("Unable to start reorg transaction");
+            return false;
+        }
+        for block in &route.retracted {
+            if self.delete_block_from_table(block).is_err() {
+                error!("Reorg failed while retracting block {}, rolling back", block.index);
+                let _ = self.db.execute("ROLLBACK;");
+                return false;
+            }
+        }
+        let mut parent = route.ancestor.clone();
+        // Blocks already validated and staged earlier in this same loop, keyed by index. Kept
+        // separate from `self.block_cache` (rather than populated into it) so that looking up
+        // a just-enacted block during revalidation of a later one sees the new data, while a
+        // rolled-back reorg still leaves the real cache pointing at the old, still-canonical
+        // chain.
+        let mut overrides: HashMap<u64, Block> = HashMap::new();
+        for block in &route.enacted {
+            if self.check_block_against(Some(&parent), block, &overrides) == Bad {
+                error!("Reorg failed re-validating block {}, rolling back", block.index);
+                let _ = self.db.execute("ROLLBACK;");
+                return false;
+            }
+            let accumulated_difficulty = self.accumulated_difficulty_after(Some(parent.index), block.difficulty);
+            if self.add_block_to_table(block.clone(), accumulated_difficulty).is_err() {
+                error!("Reorg failed while enacting block {}, rolling back", block.index);
+                let _ = self.db.execute("ROLLBACK;");
+                return false;
+            }
+            if let Some(transaction) = &block.transaction {
+                if self.add_transaction_to_table(block.index, transaction).is_err() {
+                    error!("Reorg failed while re-inserting transaction for block {}, rolling back", block.index);
+                    let _ = self.db.execute("ROLLBACK;");
+                    return false;
+                }
+            }
+            let _ = self.delete_branch_block(block);
+            overrides.insert(block.index, block.clone());
+            parent = block.clone();
+        }
+        if self.db.execute("COMMIT;").is_err() {
+            error!("Unable to commit reorg transaction");
+            return false;
+        }
+        for block in &route.retracted {
+            self.evict_block(block);
+        }
+        for block in &route.enacted {
+            self.cache_block(block);
+        }
+        self.blocks.retain(|b| !route.retracted.iter().any(|r| r.index == b.index));
+        self.blocks.extend(route.enacted.iter().cloned());
+        self.last_block = route.enacted.last().cloned();
+        self.last_full_block = route.enacted.iter().rev().find(|b| b.transaction.is_some()).cloned()
+            .or_else(|| self.last_full_block.clone());
+        self.update_deployments(self.height());
+        true
+    }
+
+    /// Handles a `Fork` block reported by [`Chain::check_new_block`]: stashes it in the branch
+    /// table, and if its branch has become heavier than our canonical chain, reorganizes onto
+    /// it by walking both tips back to their common ancestor (Parity's TreeRoute/ImportRoute
+    /// approach) and replaying the new branch.
+    pub fn handle_fork_block(&mut self, block: Block) -> bool {
+        if let Err(e) = self.store_branch_block(&block) {
+            warn!("Unable to store branch block {}: {}", block.index, e);
+            return false;
+        }
+        self.prune_stale_branch_blocks(self.height());
+        let branch_work = self.get_branch_accumulated_difficulty(&block.hash).unwrap_or(0);
+        if branch_work <= self.accumulated_difficulty() {
+            trace!("Branch ending at block {} ({}) is not heavier than our chain ({})", block.index, branch_work, self.accumulated_difficulty());
+            return false;
+        }
+        match self.build_import_route(&block) {
+            Some(route) => self.apply_import_route(route),
+            None => {
+                warn!("Could not find common ancestor for forked block {}", block.index);
+                false
+            }
+        }
+    }
+
     pub fn get_block(&self, index: u64) -> Option<Block> {
+        if let Some(block) = self.block_cache.borrow_mut().get(&index) {
+            trace!("Block {} served from cache", index);
+            return Some(block.clone());
+        }
         match self.db.prepare(SQL_GET_BLOCK_BY_ID) {
             Ok(mut statement) => {
                 statement.bind(1, index as i64).expect("Error in bind");
@@ -179,6 +693,7 @@ impl Chain {
                         }
                         Some(block) => {
                             trace!("Loaded block: {:?}", &block);
+                            self.cache_block(&block);
                             Some(block)
                         }
                     };
@@ -192,6 +707,54 @@ impl Chain {
         }
     }
 
+    /// Gets a block by hash, consulting the secondary hash -> index cache map before SQLite,
+    /// and falling back to a direct lookup in the `blocks` table if it has aged out of cache
+    pub fn get_block_by_hash(&self, hash: &Bytes) -> Option<Block> {
+        if let Some(&index) = self.block_hash_index.borrow().get(hash.as_slice()) {
+            if let Some(block) = self.block_cache.borrow_mut().get(&index) {
+                trace!("Block with hash {:?} served from cache", hash);
+                return Some(block.clone());
+            }
+        }
+        match self.db.prepare(SQL_GET_BLOCK_BY_HASH) {
+            Ok(mut statement) => {
+                statement.bind(1, hash.as_slice()).expect("Error in bind");
+                while statement.next().unwrap() == State::Row {
+                    return match Self::get_block_from_statement(&mut statement) {
+                        None => {
+                            error!("Something wrong with block in DB!");
+                            None
+                        }
+                        Some(block) => {
+                            trace!("Loaded block by hash: {:?}", &block);
+                            self.cache_block(&block);
+                            Some(block)
+                        }
+                    };
+                }
+                None
+            }
+            Err(_) => {
+                warn!("Can't find requested block with hash {:?}", hash);
+                None
+            }
+        }
+    }
+
+    /// Populates the LRU block cache and its secondary hash index, called for blocks loaded by
+    /// [`Chain::get_block`]/[`Chain::get_block_by_hash`], added by [`Chain::add_block`], or
+    /// enacted during a reorganization
+    fn cache_block(&self, block: &Block) {
+        self.block_hash_index.borrow_mut().insert(block.hash.as_slice().to_vec(), block.index);
+        self.block_cache.borrow_mut().put(block.index, block.clone());
+    }
+
+    /// Evicts a block from the cache, called when it is retracted during a reorganization
+    fn evict_block(&self, block: &Block) {
+        self.block_hash_index.borrow_mut().remove(block.hash.as_slice());
+        self.block_cache.borrow_mut().remove(&block.index);
+    }
+
     /// Gets last block that has a Transaction within
     pub fn get_last_full_block(&self) -> Option<Block> {
         match self.db.prepare(SQL_GET_LAST_FULL_BLOCK) {
@@ -279,11 +842,11 @@ impl Chain {
         let mut statement = self.db.prepare(SQL_GET_TRANSACTION_BY_ID).unwrap();
         statement.bind(1, identity_hash.as_slice()).expect("Error in bind");
         while let State::Row = statement.next().unwrap() {
-            let identity = Bytes::from_bytes(statement.read::<Vec<u8>>(1).unwrap().as_slice());
-            let confirmation = Bytes::from_bytes(statement.read::<Vec<u8>>(2).unwrap().as_slice());
-            let method = statement.read::<String>(3).unwrap();
-            let data = statement.read::<String>(4).unwrap();
-            let pub_key = Bytes::from_bytes(statement.read::<Vec<u8>>(5).unwrap().as_slice());
+            let identity = Bytes::from_bytes(statement.read::<Vec<u8>>(2).unwrap().as_slice());
+            let confirmation = Bytes::from_bytes(statement.read::<Vec<u8>>(3).unwrap().as_slice());
+            let method = statement.read::<String>(4).unwrap();
+            let data = statement.read::<String>(5).unwrap();
+            let pub_key = Bytes::from_bytes(statement.read::<Vec<u8>>(6).unwrap().as_slice());
             let transaction = Transaction { identity, confirmation, method, data, pub_key };
             debug!("Found transaction for domain {}: {:?}", domain, &transaction);
             if transaction.check_identity(domain) {
@@ -332,6 +895,20 @@ impl Chain {
 
     /// Check if this block can be added to our blockchain
     pub fn check_new_block(&self, block: &Block) -> BlockQuality {
+        self.check_block_against(self.last_block.as_ref(), block, &HashMap::new())
+    }
+
+    /// Core validation of `block` against an explicit `parent`, independent of
+    /// `self.last_block`. [`Chain::check_new_block`] calls this with our current tip; during a
+    /// reorg, [`Chain::apply_import_route`] calls it with each enacted block's real
+    /// predecessor in the new branch instead, since `self.last_block` still points at the old
+    /// tip until the whole route has committed.
+    ///
+    /// `overrides` lets a caller mid-reorg supply blocks that have been staged in this
+    /// transaction but aren't reflected in `self.block_cache`/`self.blocks` yet (and must not
+    /// be, in case the reorg rolls back); lookups by index consult it before falling back to
+    /// [`Chain::get_block`].
+    fn check_block_against(&self, parent: Option<&Block>, block: &Block, overrides: &HashMap<u64, Block>) -> BlockQuality {
         let timestamp = Utc::now().timestamp();
         if block.timestamp > timestamp {
             warn!("Ignoring block from the future:\n{:?}", &block);
@@ -363,7 +940,7 @@ impl Chain {
                 return Bad;
             }
         }
-        match &self.last_block {
+        match parent {
             None => {
                 if !block.is_genesis() {
                     warn!("Block is from the future, how is this possible?");
@@ -375,6 +952,12 @@ impl Chain {
                 }
             }
             Some(last_block) => {
+                if let Some(median) = self.median_time_past_with(last_block.index, overrides) {
+                    if block.timestamp <= median {
+                        warn!("Block {} timestamp {} is not greater than median-time-past {}", block.index, block.timestamp, median);
+                        return Bad;
+                    }
+                }
                 if block.timestamp < last_block.timestamp && block.index > last_block.index {
                     warn!("Ignoring block with timestamp/index collision:\n{:?}", &block);
                     return Bad;
@@ -388,7 +971,7 @@ impl Chain {
                         warn!("Ignoring block {}, we already have it", block.index);
                         return Twin;
                     }
-                    if let Some(my_block) = self.get_block(block.index) {
+                    if let Some(my_block) = overrides.get(&block.index).cloned().or_else(|| self.get_block(block.index)) {
                         return if my_block.hash != block.hash {
                             warn!("Got forked block {} with hash {:?} instead of {:?}", block.index, block.hash, last_block.hash);
                             Fork
@@ -399,7 +982,7 @@ impl Chain {
                     }
                 }
                 if block.transaction.is_none() {
-                    if let Some(locker) = self.get_block_locker(&last_block, block.timestamp) {
+                    if let Some(locker) = self.get_block_locker_with(&last_block, block.timestamp, overrides) {
                         if locker != block.pub_key {
                             warn!("Ignoring block {}, as wrong locker", block.index);
                             return Bad;
@@ -412,8 +995,40 @@ impl Chain {
         Good
     }
 
+    /// Computes the BIP113-style median-time-past: the median timestamp of up to the last
+    /// `MEDIAN_TIME_SPAN` blocks ending at (and including) `at_index`, fewer near genesis
+    pub fn median_time_past(&self, at_index: u64) -> Option<i64> {
+        self.median_time_past_with(at_index, &HashMap::new())
+    }
+
+    /// Same as [`Chain::median_time_past`], but consults `overrides` for a block index before
+    /// falling back to [`Chain::get_block`] — see [`Chain::check_block_against`].
+    fn median_time_past_with(&self, at_index: u64, overrides: &HashMap<u64, Block>) -> Option<i64> {
+        let mut timestamps = Vec::new();
+        let mut index = at_index;
+        loop {
+            if let Some(block) = overrides.get(&index).cloned().or_else(|| self.get_block(index)) {
+                timestamps.push(block.timestamp);
+            }
+            if timestamps.len() as u64 >= MEDIAN_TIME_SPAN || index == 0 {
+                break;
+            }
+            index -= 1;
+        }
+        if timestamps.is_empty() {
+            return None;
+        }
+        Some(median(&mut timestamps))
+    }
+
     /// Gets a public key of a node that needs to mine "locker" block above this block
     pub fn get_block_locker(&self, block: &Block, timestamp: i64) -> Option<Bytes> {
+        self.get_block_locker_with(block, timestamp, &HashMap::new())
+    }
+
+    /// Same as [`Chain::get_block_locker`], but consults `overrides` for a block index before
+    /// falling back to [`Chain::get_block`] — see [`Chain::check_block_against`].
+    fn get_block_locker_with(&self, block: &Block, timestamp: i64, overrides: &HashMap<u64, Block>) -> Option<Bytes> {
         if block.hash.is_empty() || block.hash.is_zero() {
             return None;
         }
@@ -429,12 +1044,14 @@ impl Chain {
             }
             None => {}
         }
-        // How many 5 min intervals have passed since this block?
-        let intervals = ((timestamp - block.timestamp) / LOCKER_BLOCK_INTERVAL) as u64;
+        // How many 5 min intervals have passed since this block? Uses the median-time-past
+        // as a stable reference instead of the block's own (potentially skewed) timestamp.
+        let reference_time = self.median_time_past_with(block.index, overrides).unwrap_or(block.timestamp);
+        let intervals = ((timestamp - reference_time) / LOCKER_BLOCK_INTERVAL) as u64;
         let tail = block.hash.get_tail_u64();
         let start_index = 1 + ((tail + tail * intervals) % (block.index - 2));
         for index in start_index..block.index {
-            if let Some(b) = self.get_block(index) {
+            if let Some(b) = overrides.get(&index).cloned().or_else(|| self.get_block(index)) {
                 if b.pub_key != block.pub_key {
                     trace!("Locker block for block {} must be mined by owner of block {} block_hash: {:?}", block.index, b.index, block.hash);
                     return Some(b.pub_key);
@@ -444,6 +1061,106 @@ impl Chain {
         None
     }
 
+    /// Whether a version-bits deployment is active as of `at_height`, so callers like
+    /// [`Chain::check_new_block`] can gate a new consensus rule behind network signaling
+    pub fn is_deployment_active(&self, bit: u8, at_height: u64) -> bool {
+        let status = self.get_deployment_status(bit);
+        status.state == DeploymentState::Active && at_height >= status.active_since_height.unwrap_or(0)
+    }
+
+    /// Version a newly mined block should be stamped with: `self.version` with the bit of
+    /// every `STARTED` deployment set, so the block actually signals readiness. Without this,
+    /// a deployment's rolling window never sees a single signal and it sits in `STARTED`
+    /// forever. Mining code must build new blocks from this instead of `self.version` directly.
+    pub fn next_block_version(&self) -> u32 {
+        let mut version = self.version;
+        for deployment in DEPLOYMENTS {
+            if self.get_deployment_status(deployment.bit).state == DeploymentState::Started {
+                version |= 1 << deployment.bit;
+            }
+        }
+        version
+    }
+
+    /// Counts how many of the up-to-`DEPLOYMENT_WINDOW` blocks ending at `end_index` set the
+    /// given signaling bit in `Block.version`
+    fn count_signaling(&self, bit: u8, end_index: u64) -> (u64, u64) {
+        let start = end_index.saturating_sub(DEPLOYMENT_WINDOW.saturating_sub(1));
+        let mut set = 0u64;
+        let mut total = 0u64;
+        for index in start..=end_index {
+            if let Some(block) = self.get_block(index) {
+                total += 1;
+                if block.version & (1 << bit) != 0 {
+                    set += 1;
+                }
+            }
+        }
+        (set, total)
+    }
+
+    /// Recomputes and persists the BIP9-style state of every known deployment as of `height`
+    fn update_deployments(&mut self, height: u64) {
+        for deployment in DEPLOYMENTS {
+            let status = self.get_deployment_status(deployment.bit);
+            let (set, total) = if status.state == DeploymentState::Started {
+                self.count_signaling(deployment.bit, height)
+            } else {
+                (0, 0)
+            };
+            let new_status = next_deployment_status(status, height, set, total);
+            if new_status.state != status.state {
+                match new_status.state {
+                    DeploymentState::LockedIn => info!("Deployment '{}' (bit {}) locked in at height {}", deployment.name, deployment.bit, height),
+                    DeploymentState::Active => info!("Deployment '{}' (bit {}) activated at height {}", deployment.name, deployment.bit, height),
+                    _ => {}
+                }
+                self.set_deployment_status(deployment.bit, new_status);
+            }
+        }
+    }
+
+    /// Reads a deployment's persisted state, defaulting to `DEFINED` the first time it's seen
+    fn get_deployment_status(&self, bit: u8) -> DeploymentStatus {
+        if let Some(status) = self.deployments.borrow().get(&bit) {
+            return *status;
+        }
+        let status = match self.db.prepare(SQL_GET_DEPLOYMENT_STATE) {
+            Ok(mut statement) => {
+                statement.bind(1, bit as i64).expect("Error in bind");
+                if statement.next().unwrap() == State::Row {
+                    let state = DeploymentState::from_str(&statement.read::<String>(0).unwrap());
+                    let locked_in_height = statement.read::<i64>(1).ok().map(|v| v as u64);
+                    let active_since_height = statement.read::<i64>(2).ok().map(|v| v as u64);
+                    DeploymentStatus { state, locked_in_height, active_since_height }
+                } else {
+                    DeploymentStatus { state: DeploymentState::Defined, locked_in_height: None, active_since_height: None }
+                }
+            }
+            Err(_) => DeploymentStatus { state: DeploymentState::Defined, locked_in_height: None, active_since_height: None },
+        };
+        self.deployments.borrow_mut().insert(bit, status);
+        status
+    }
+
+    /// Persists a deployment's new state so it survives restarts
+    fn set_deployment_status(&self, bit: u8, status: DeploymentStatus) {
+        if let Ok(mut statement) = self.db.prepare(SQL_SET_DEPLOYMENT_STATE) {
+            let _ = statement.bind(1, bit as i64);
+            let _ = statement.bind(2, status.state.as_str());
+            match status.locked_in_height {
+                Some(h) => { let _ = statement.bind(3, h as i64); }
+                None => { let _ = statement.bind(3, ()); }
+            }
+            match status.active_since_height {
+                Some(h) => { let _ = statement.bind(4, h as i64); }
+                None => { let _ = statement.bind(4, ()); }
+            }
+            let _ = statement.next();
+        }
+        self.deployments.borrow_mut().insert(bit, status);
+    }
+
     fn get_block_from_statement(statement: &mut Statement) -> Option<Block> {
         let index = statement.read::<i64>(0).unwrap() as u64;
         let timestamp = statement.read::<i64>(1).unwrap();
@@ -458,4 +1175,163 @@ impl Chain {
         let signature = Bytes::from_bytes(statement.read::<Vec<u8>>(10).unwrap().as_slice());
         Some(Block::from_all_params(index, timestamp, version, difficulty, random, nonce, prev_block_hash, hash, pub_key, signature, transaction))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{median, next_deployment_status, DeploymentState, DeploymentStatus, DEPLOYMENT_WINDOW};
+
+    #[test]
+    fn median_of_single_value_is_itself() {
+        assert_eq!(median(&mut vec![42]), 42);
+    }
+
+    #[test]
+    fn median_of_odd_count_is_middle_value() {
+        assert_eq!(median(&mut vec![5, 1, 3]), 3);
+    }
+
+    #[test]
+    fn median_of_even_count_is_upper_middle_value() {
+        assert_eq!(median(&mut vec![1, 2, 3, 4]), 3);
+    }
+
+    #[test]
+    fn median_is_resistant_to_a_single_outlier() {
+        assert_eq!(median(&mut vec![10, 11, 12, 13, 1_000_000]), 12);
+    }
+
+    fn defined() -> DeploymentStatus {
+        DeploymentStatus { state: DeploymentState::Defined, locked_in_height: None, active_since_height: None }
+    }
+
+    #[test]
+    fn stays_defined_before_the_first_window_closes() {
+        let status = next_deployment_status(defined(), DEPLOYMENT_WINDOW - 2, 0, 0);
+        assert_eq!(status.state, DeploymentState::Defined);
+    }
+
+    #[test]
+    fn starts_once_the_first_window_closes() {
+        let status = next_deployment_status(defined(), DEPLOYMENT_WINDOW - 1, 0, 0);
+        assert_eq!(status.state, DeploymentState::Started);
+    }
+
+    #[test]
+    fn stays_started_below_the_signaling_threshold() {
+        let started = DeploymentStatus { state: DeploymentState::Started, locked_in_height: None, active_since_height: None };
+        let status = next_deployment_status(started, 500, 74, 100);
+        assert_eq!(status.state, DeploymentState::Started);
+    }
+
+    #[test]
+    fn locks_in_once_the_signaling_threshold_is_met() {
+        let started = DeploymentStatus { state: DeploymentState::Started, locked_in_height: None, active_since_height: None };
+        let status = next_deployment_status(started, 500, 75, 100);
+        assert_eq!(status.state, DeploymentState::LockedIn);
+        assert_eq!(status.locked_in_height, Some(500));
+    }
+
+    #[test]
+    fn activates_one_window_after_locking_in() {
+        let locked_in = DeploymentStatus { state: DeploymentState::LockedIn, locked_in_height: Some(500), active_since_height: None };
+        let too_soon = next_deployment_status(locked_in, 500 + DEPLOYMENT_WINDOW - 1, 0, 0);
+        assert_eq!(too_soon.state, DeploymentState::LockedIn);
+        let status = next_deployment_status(locked_in, 500 + DEPLOYMENT_WINDOW, 0, 0);
+        assert_eq!(status.state, DeploymentState::Active);
+        assert_eq!(status.active_since_height, Some(500 + DEPLOYMENT_WINDOW));
+    }
+
+    use super::Chain;
+    use crate::{Block, Bytes};
+    use std::collections::HashMap;
+
+    fn test_block(index: u64, hash_byte: u8, prev_hash_byte: u8) -> Block {
+        Block::from_all_params(
+            index,
+            0,
+            super::CHAIN_VERSION,
+            0,
+            0,
+            0,
+            Bytes::from_bytes(&[prev_hash_byte]),
+            Bytes::from_bytes(&[hash_byte]),
+            Bytes::default(),
+            Bytes::default(),
+            None,
+        )
+    }
+
+    // Builds a simple fork:
+    //   canonical: 0 -> 1 -> 2a
+    //   branch:            -> 2b -> 3b (tip)
+    // so block 1 is the common ancestor, 2a is retracted, and 2b/3b are enacted.
+    #[test]
+    fn compute_import_route_finds_common_ancestor_and_switches_branches() {
+        let genesis = test_block(0, 0, 0);
+        let block1 = test_block(1, 1, 0);
+        let block2a = test_block(2, 2, 1);
+        let block2b = test_block(2, 20, 1);
+        let block3b = test_block(3, 30, 20);
+
+        let mut canonical = HashMap::new();
+        canonical.insert(0, genesis);
+        canonical.insert(1, block1.clone());
+        canonical.insert(2, block2a.clone());
+
+        let mut branch = HashMap::new();
+        branch.insert(block2b.hash.as_slice().to_vec(), block2b.clone());
+        branch.insert(block3b.prev_block_hash.as_slice().to_vec(), block2b.clone());
+
+        let route = Chain::compute_import_route(
+            block3b.clone(),
+            2,
+            |index| canonical.get(&index).cloned(),
+            |hash| branch.get(hash.as_slice()).cloned(),
+        )
+        .expect("route should be found");
+
+        assert_eq!(route.ancestor.index, 1);
+        assert_eq!(route.retracted.iter().map(|b| b.index).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(route.enacted.iter().map(|b| b.index).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(route.enacted[0].hash, block2b.hash);
+        assert_eq!(route.enacted[1].hash, block3b.hash);
+    }
+
+    #[test]
+    fn compute_import_route_is_none_when_branch_never_reconnects() {
+        let block3b = test_block(3, 30, 20);
+        let canonical: HashMap<u64, Block> = HashMap::new();
+        let branch: HashMap<Vec<u8>, Block> = HashMap::new();
+
+        let route = Chain::compute_import_route(
+            block3b,
+            2,
+            |index| canonical.get(&index).cloned(),
+            |hash| branch.get(hash.as_slice()).cloned(),
+        );
+
+        assert!(route.is_none());
+    }
+
+    #[test]
+    fn compute_import_route_rejects_branch_block_with_inconsistent_index() {
+        // block3b claims index 3 with prev_block_hash pointing at a block whose hash matches,
+        // but that block's own index is 7, not the expected 2 — a malformed/hostile branch.
+        let bogus_parent = test_block(7, 20, 1);
+        let block3b = test_block(3, 30, 20);
+
+        let canonical: HashMap<u64, Block> = HashMap::new();
+        let mut branch = HashMap::new();
+        branch.insert(bogus_parent.hash.as_slice().to_vec(), bogus_parent.clone());
+
+        let route = Chain::compute_import_route(
+            block3b,
+            2,
+            |index| canonical.get(&index).cloned(),
+            |hash| branch.get(hash.as_slice()).cloned(),
+        );
+
+        assert!(route.is_none());
+    }
 }
\ No newline at end of file